@@ -1,11 +1,91 @@
 use std::{
-    collections::HashSet,
+    borrow::Cow,
+    collections::VecDeque,
     io::{self, Write},
 };
 
+/// A 1-based line/column location of a character in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub col: usize,
+}
+
+/// The `[start, end)` byte-offset range a token spans in the source, along
+/// with the `Position` of its first character.
+///
+/// These are byte offsets into the source `&str`, not char offsets, so a
+/// span can only be used to slice the exact `&str` the `Lexer` was built
+/// from; a source containing multi-byte UTF-8 has offsets that skip ahead
+/// by more than one per character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub pos: Position,
+}
+
+/// An error produced while lexing malformed or unexpected source input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexError {
+    UnexpectedCharacter { c: char, span: Span },
+    ExpectedCharacter { expected: char, actual: char, span: Span },
+    UnexpectedEndOfFile { span: Span },
+    InvalidEscape { c: char, span: Span },
+    BadIndent { count: usize, span: Span },
+}
+
+impl LexError {
+    pub fn span(&self) -> Span {
+        match *self {
+            LexError::UnexpectedCharacter { span, .. }
+            | LexError::ExpectedCharacter { span, .. }
+            | LexError::UnexpectedEndOfFile { span, .. }
+            | LexError::InvalidEscape { span, .. }
+            | LexError::BadIndent { span, .. } => span,
+        }
+    }
+}
+
+impl std::fmt::Display for LexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Position { line, col } = self.span().pos;
+        write!(f, "{line}:{col}: ")?;
+        match self {
+            LexError::UnexpectedCharacter { c, .. } => write!(f, "unexpected character '{c}'"),
+            LexError::ExpectedCharacter {
+                expected, actual, ..
+            } => write!(f, "expected '{expected}', found '{actual}'"),
+            LexError::UnexpectedEndOfFile { .. } => write!(f, "unexpected end of file"),
+            LexError::InvalidEscape { c, .. } => write!(f, "invalid escape character '{c}'"),
+            LexError::BadIndent { count, .. } => {
+                write!(f, "indentation of {count} spaces is not a multiple of 4")
+            }
+        }
+    }
+}
+
+impl std::error::Error for LexError {}
+
+/// Which part of a (possibly interpolation-split) string literal a
+/// `Token::StrLit` covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StrFragment {
+    /// A complete literal: carries both the opening and closing quote(s).
+    Whole,
+    /// The first segment of an interpolated f-string: carries the prefix
+    /// and opening quote(s), but no closing quote.
+    Start,
+    /// A segment between two interpolations: carries neither quote.
+    Middle,
+    /// The last segment of an interpolated f-string: carries the closing
+    /// quote(s), but no opening quote.
+    End,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Token {
-    Ident(String),
+pub enum Token<'src> {
+    Ident(&'src str),
     LeftParen,
     RightParen,
     LeftCurly,
@@ -48,14 +128,26 @@ pub enum Token {
     RightShiftEquals,
     Indent(usize),
     StrLit {
-        tags: HashSet<char>,
-        val: String,
+        /// The prefix characters (e.g. `f`, `r`, `b`), in source order.
+        tags: Vec<char>,
+        quote: char,
+        triple: bool,
+        /// Which part of the literal this token covers; an f-string with
+        /// interpolation is split into several `StrLit`s (plus
+        /// `FStringStart`/`FStringEnd` pairs), so only the first opens a
+        /// quote and only the last closes one.
+        frag: StrFragment,
+        val: Cow<'src, str>,
     },
-    Comment(String),
+    /// The `{` that opens an interpolated expression inside an f-string.
+    FStringStart,
+    /// The `}` that closes an interpolated expression inside an f-string.
+    FStringEnd,
+    Comment(&'src str),
     /// Contains a string since python integers are infinitely sized
-    IntLit(String),
+    IntLit(&'src str),
     /// Contains a string since python floats are infinitely sized
-    FloatLit(String),
+    FloatLit(&'src str),
 
     BooleanLit(bool),
 
@@ -97,7 +189,7 @@ pub enum Token {
     DoublePipe,
 }
 
-impl Token {
+impl Token<'_> {
     pub fn write_to<W>(&self, w: &mut W) -> io::Result<()>
     where
         W: Write,
@@ -153,11 +245,41 @@ impl Token {
             Token::RightShift => write_str!(">>"),
             Token::RightShiftEquals => write_str!(">>="),
             Token::Indent(n) => write_str!("    ".repeat(*n)),
-            Token::StrLit { tags, val } => {
+            Token::StrLit {
+                tags,
+                quote,
+                triple,
+                frag,
+                val,
+            } => {
                 let tags: String = tags.iter().collect();
-                let val = val.replace('\'', "\\\'");
-                write!(w, "{}'{}'", tags, val)?;
+                let delim: String = quote.to_string().repeat(if *triple { 3 } else { 1 });
+                let raw = tags.contains('r') || tags.contains('R');
+                let body = if raw || *triple {
+                    val.to_string()
+                } else {
+                    val.replace(*quote, &format!("\\{quote}"))
+                };
+                let (open, close) = match frag {
+                    StrFragment::Whole => (true, true),
+                    StrFragment::Start => (true, false),
+                    StrFragment::Middle => (false, false),
+                    StrFragment::End => (false, true),
+                };
+                if open {
+                    write!(w, "{tags}{delim}")?;
+                }
+                write!(w, "{body}")?;
+                if close {
+                    write!(w, "{delim}")?;
+                }
             }
+            // A space after the opening `{`/before the closing `}` keeps a
+            // literal brace immediately inside the interpolation (e.g. a
+            // dict) from reading as an escaped `{{`/`}}` instead of a
+            // reopened interpolation.
+            Token::FStringStart => write_str!("{ "),
+            Token::FStringEnd => write_str!(" }"),
             Token::Comment(text) => write!(w, "#{}", text)?,
             Token::IntLit(n) => write_str!(n, s),
             Token::FloatLit(n) => write_str!(n, s),
@@ -202,68 +324,249 @@ impl Token {
     }
 }
 
-pub struct Lexer {
-    chars: Vec<char>,
+/// The fixed properties of a string literal, gathered once its prefix and
+/// opening quote(s) have been read, and shared by every segment that
+/// literal expands into.
+struct StrLitHead<'a, 'src> {
+    tags: &'a Vec<char>,
+    quote: char,
+    triple: bool,
+    src: &'src str,
+    start: usize,
+    start_pos: Position,
+}
+
+pub struct Lexer<'src> {
+    src: &'src str,
     pos: usize,
     start_of_line: bool,
+    line: usize,
+    col: usize,
+    /// Tokens already produced but not yet handed out: the tail of an
+    /// f-string split across `FStringStart`/`FStringEnd`, and/or tokens
+    /// lexed ahead of time by `peek_token`/`peek_second`. Drained before
+    /// lexing fresh input.
+    pending: VecDeque<Result<(Token<'src>, Span), LexError>>,
+    /// Number of `{` seen without a matching `}` yet. Indentation inside a
+    /// brace block is discarded by the transpiler rather than being
+    /// meaningful, so it's exempted from the multiple-of-4 check below.
+    brace_depth: usize,
 }
 
-impl Lexer {
-    pub fn new(chars: Vec<char>) -> Self {
+impl<'src> Lexer<'src> {
+    pub fn new(src: &'src str) -> Self {
         Self {
-            chars,
+            src,
             pos: 0,
             start_of_line: true,
+            line: 1,
+            col: 1,
+            pending: VecDeque::new(),
+            brace_depth: 0,
         }
     }
 
-    fn peek_char(&self) -> char {
-        self.chars[self.pos]
+    /// Advance `col` by one column, for every character that isn't a newline.
+    fn advance(&mut self) {
+        self.col += 1;
     }
 
-    fn take_char(&mut self) -> char {
-        self.pos += 1; // increase position
-        self.chars[self.pos - 1] // return prev char
+    /// Reset `col` and bump `line`, for a consumed `'\n'`.
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.col = 1;
     }
 
-    fn chars_left(&self) -> usize {
-        self.chars.len() - self.pos
+    /// The current `(line, col)`, as a zero-width `Span` for point errors.
+    fn here(&self) -> Span {
+        Span {
+            start: self.pos,
+            end: self.pos,
+            pos: Position {
+                line: self.line,
+                col: self.col,
+            },
+        }
     }
 
-    fn take_ident(&mut self) -> &[char] {
+    fn peek_char(&self) -> Result<char, LexError> {
+        self.src[self.pos..]
+            .chars()
+            .next()
+            .ok_or(LexError::UnexpectedEndOfFile { span: self.here() })
+    }
+
+    /// Look two characters ahead without consuming anything, returning
+    /// `None` rather than erroring if the source ends before then.
+    fn peek_second_char(&self) -> Option<char> {
+        self.src[self.pos..].chars().nth(1)
+    }
+
+    fn take_char(&mut self) -> Result<char, LexError> {
+        let c = self.peek_char()?;
+        self.pos += c.len_utf8(); // increase position
+        if c == '\n' {
+            self.new_line();
+        } else {
+            self.advance();
+        }
+        Ok(c)
+    }
+
+    /// Put back a just-taken char that is known not to be `'\n'`, so it can
+    /// be re-lexed by a more specific `take_*` method.
+    ///
+    /// Only ever called right after taking an ASCII char, so `pos` always
+    /// rewinds by exactly one byte.
+    fn untake_char(&mut self) {
+        self.pos -= 1;
+        self.col -= 1;
+    }
+
+    fn at_eof(&self) -> bool {
+        self.pos >= self.src.len()
+    }
+
+    /// Whether the characters after the just-taken prefix letter look like
+    /// the rest of a string-literal prefix (e.g. the `b` in `rb"..."`, or
+    /// nothing at all) followed by an opening quote.
+    fn peek_is_str_prefix_tail(&self) -> bool {
+        let mut chars = self.src[self.pos..].chars();
+        match chars.next() {
+            Some(c) if c.is_ascii_alphabetic() => matches!(chars.next(), Some('\'' | '"')),
+            Some('\'' | '"') => true,
+            _ => false,
+        }
+    }
+
+    fn take_ident(&mut self) -> &'src str {
+        let src = self.src;
         let i = self.pos;
-        while matches!(self.peek_char(), 'a'..='z' | 'A'..='Z' | '0'..='9' | '_') {
-            self.take_char();
+        while matches!(self.peek_char(), Ok('a'..='z' | 'A'..='Z' | '0'..='9' | '_')) {
+            self.take_char().expect("just peeked a char");
         }
         assert_ne!(self.pos, i, "Ident had 0 characters");
-        &self.chars[i..self.pos]
+        &src[i..self.pos]
     }
 
-    fn take_str_lit(&mut self) -> Token {
-        let mut tags = HashSet::new();
-        let mut tag = self.take_char();
+    /// Push a finished `StrLit` segment onto `results`. The very first
+    /// segment's span starts at the literal's own start (its prefix and
+    /// opening quote(s)), every one after it (the ones that follow an
+    /// f-string interpolation) at its own content; the segment that ends the
+    /// literal also spans the closing quote(s). `is_last` says whether this segment ends the
+    /// literal (as opposed to being cut short by an interpolation), and
+    /// together with whether `results` is still empty determines which
+    /// quote(s) the segment is responsible for emitting.
+    fn push_str_segment(
+        head: &StrLitHead<'_, 'src>,
+        results: &mut Vec<(Token<'src>, Span)>,
+        owned: Option<String>,
+        segment_start: usize,
+        segment_pos: Position,
+        end: usize,
+        is_last: bool,
+    ) {
+        let is_first = results.is_empty();
+        let val = match owned {
+            Some(s) => Cow::Owned(s),
+            None => Cow::Borrowed(&head.src[segment_start..end]),
+        };
+        let frag = match (is_first, is_last) {
+            (true, true) => StrFragment::Whole,
+            (true, false) => StrFragment::Start,
+            (false, true) => StrFragment::End,
+            (false, false) => StrFragment::Middle,
+        };
+        let tok = Token::StrLit {
+            tags: head.tags.clone(),
+            quote: head.quote,
+            triple: head.triple,
+            frag,
+            val,
+        };
+        let (span_start, span_pos) = if is_first {
+            (head.start, head.start_pos)
+        } else {
+            (segment_start, segment_pos)
+        };
+        // `end` is where the closing quote(s) begin; both quotes are ASCII.
+        let span_end = if is_last {
+            end + if head.triple { 3 } else { 1 }
+        } else {
+            end
+        };
+        results.push((
+            tok,
+            Span {
+                start: span_start,
+                end: span_end,
+                pos: span_pos,
+            },
+        ));
+    }
+
+    /// Lex a (possibly prefixed, possibly triple-quoted, possibly
+    /// f-string) string literal starting at the already-consumed `start`.
+    ///
+    /// For an f-string containing interpolations this yields more than one
+    /// token (the literal segments plus `FStringStart`/`FStringEnd` pairs
+    /// around each nested expression); only the first is returned, the
+    /// rest are queued in `self.pending` for subsequent `lex` calls.
+    fn take_str_lit(&mut self, start: usize, start_pos: Position) -> Result<(Token<'src>, Span), LexError> {
+        let src = self.src;
+        let mut tags = Vec::new();
+        let mut tag = self.take_char()?;
         while tag != '\'' && tag != '"' {
-            tags.insert(tag);
-            tag = self.take_char();
+            tags.push(tag);
+            tag = self.take_char()?;
         }
         let quote = tag;
-        assert!(quote == '\'' || quote == '"');
-
-        // TODO: triple quotes
-        // let triple = self.peek_char() == quote;
-        // if triple {
-        //     self.take_char();
-        //     assert_eq!(self.take_char(), quote);
-        // }
-        let mut out = String::new();
+
+        let triple = matches!(self.peek_char(), Ok(c) if c == quote)
+            && self.peek_second_char() == Some(quote);
+        if triple {
+            self.take_char()?;
+            self.take_char()?;
+        }
+
+        let raw = tags.contains(&'r') || tags.contains(&'R');
+        let is_f = tags.contains(&'f') || tags.contains(&'F');
+        let head = StrLitHead {
+            tags: &tags,
+            quote,
+            triple,
+            src,
+            start,
+            start_pos,
+        };
+
+        let mut results = Vec::new();
+        let mut segment_start = self.pos;
+        let mut segment_pos = Position {
+            line: self.line,
+            col: self.col,
+        };
+        // Stays `None` (borrowing straight from `src`) as long as no escape
+        // is seen; the first escape allocates and copies what's been seen
+        // so far, after which every char is pushed onto it.
+        let mut owned: Option<String> = None;
         let mut escape_next = false;
         loop {
-            match self.take_char() {
-                '\\' => {
+            let before = self.pos;
+            let before_pos = Position {
+                line: self.line,
+                col: self.col,
+            };
+            match self.take_char()? {
+                '\\' if !raw && !escape_next => {
+                    let buf = owned.get_or_insert_with(String::new);
+                    if buf.is_empty() {
+                        buf.push_str(&src[segment_start..before]);
+                    }
                     escape_next = true;
                 }
                 c if escape_next => {
-                    out.push(match c {
+                    let resolved = match c {
                         '\'' | '"' => c,
                         '\\' => '\\',
                         'n' => '\n',
@@ -272,83 +575,204 @@ impl Lexer {
                         'x' => {
                             // hex character
                             let mut s = String::with_capacity(2);
-                            s.push(self.take_char());
-                            s.push(self.take_char());
-                            char::from_u32(u32::from_str_radix(&s, 16).unwrap()).unwrap()
+                            s.push(self.take_char()?);
+                            s.push(self.take_char()?);
+                            u32::from_str_radix(&s, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or(LexError::InvalidEscape {
+                                    c: 'x',
+                                    span: self.here(),
+                                })?
                         }
                         '0' => {
                             // octal character
                             let mut s = String::with_capacity(2);
-                            s.push(self.take_char());
-                            s.push(self.take_char());
-                            char::from_u32(u32::from_str_radix(&s, 8).unwrap()).unwrap()
+                            s.push(self.take_char()?);
+                            s.push(self.take_char()?);
+                            u32::from_str_radix(&s, 8)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or(LexError::InvalidEscape {
+                                    c: '0',
+                                    span: self.here(),
+                                })?
                         }
                         _ => {
-                            panic!("Unexpected escaped char: '{}'", c)
+                            return Err(LexError::InvalidEscape {
+                                c,
+                                span: self.here(),
+                            });
                         }
-                    });
+                    };
+                    owned
+                        .as_mut()
+                        .expect("owned buffer started by the preceding backslash")
+                        .push(resolved);
                     escape_next = false;
                 }
-                c if c == quote => break,
+                '{' if is_f && matches!(self.peek_char(), Ok('{')) => {
+                    self.take_char()?;
+                    let buf = owned.get_or_insert_with(String::new);
+                    if buf.is_empty() {
+                        buf.push_str(&src[segment_start..before]);
+                    }
+                    buf.push('{');
+                }
+                '}' if is_f && matches!(self.peek_char(), Ok('}')) => {
+                    self.take_char()?;
+                    let buf = owned.get_or_insert_with(String::new);
+                    if buf.is_empty() {
+                        buf.push_str(&src[segment_start..before]);
+                    }
+                    buf.push('}');
+                }
+                '{' if is_f => {
+                    Self::push_str_segment(
+                        &head,
+                        &mut results,
+                        owned.take(),
+                        segment_start,
+                        segment_pos,
+                        before,
+                        false,
+                    );
+                    results.push((
+                        Token::FStringStart,
+                        Span {
+                            start: before,
+                            end: self.pos,
+                            pos: before_pos,
+                        },
+                    ));
+                    // The matching `}` is lexed by `lex_one`, which
+                    // decrements `self.brace_depth`, so count this `{` too.
+                    self.brace_depth += 1;
+                    // Track brace depth so a nested `{}` inside the
+                    // interpolated expression (e.g. a dict literal) doesn't
+                    // end the interpolation before its matching `}`.
+                    let mut brace_depth = 0usize;
+                    loop {
+                        match self.lex_one()? {
+                            Some((Token::LeftCurly, span)) => {
+                                brace_depth += 1;
+                                results.push((Token::LeftCurly, span));
+                            }
+                            Some((Token::RightCurly, span)) if brace_depth == 0 => {
+                                results.push((Token::FStringEnd, span));
+                                break;
+                            }
+                            Some((Token::RightCurly, span)) => {
+                                brace_depth -= 1;
+                                results.push((Token::RightCurly, span));
+                            }
+                            Some(tok) => results.push(tok),
+                            None => return Err(LexError::UnexpectedEndOfFile { span: self.here() }),
+                        }
+                    }
+                    segment_start = self.pos;
+                    segment_pos = Position {
+                        line: self.line,
+                        col: self.col,
+                    };
+                }
+                c if c == quote => {
+                    if triple {
+                        let is_end = matches!(self.peek_char(), Ok(c) if c == quote)
+                            && self.peek_second_char() == Some(quote);
+                        if is_end {
+                            self.take_char()?;
+                            self.take_char()?;
+                            Self::push_str_segment(
+                                &head,
+                                &mut results,
+                                owned.take(),
+                                segment_start,
+                                segment_pos,
+                                before,
+                                true,
+                            );
+                            break;
+                        }
+                        if let Some(buf) = owned.as_mut() {
+                            buf.push(c);
+                        }
+                    } else {
+                        Self::push_str_segment(
+                            &head,
+                            &mut results,
+                            owned.take(),
+                            segment_start,
+                            segment_pos,
+                            before,
+                            true,
+                        );
+                        break;
+                    }
+                }
                 c => {
-                    out.push(c);
+                    if let Some(buf) = owned.as_mut() {
+                        buf.push(c);
+                    }
                 }
             }
         }
-        // TODO: triple quotes
-        // if triple {
-        //     assert_eq!(self.take_char(), quote);
-        //     assert_eq!(self.take_char(), quote);
-        // }
 
-        Token::StrLit { tags, val: out }
+        let mut results = results.into_iter();
+        let first = results
+            .next()
+            .expect("a string literal always yields at least one token");
+        self.pending.extend(results.map(Ok));
+        Ok(first)
     }
 
-    fn take_comment(&mut self) -> String {
-        let mut out = String::new();
+    fn take_comment(&mut self) -> &'src str {
+        let src = self.src;
+        let i = self.pos;
         loop {
-            if self.chars_left() == 0 || self.peek_char() == '\n' {
-                return out;
+            if self.at_eof() || matches!(self.peek_char(), Ok('\n')) {
+                return &src[i..self.pos];
             }
-            out.push(self.take_char());
+            self.take_char().expect("just checked not at eof");
         }
     }
 
-    fn take_number(&mut self) -> Token {
+    fn take_number(&mut self) -> Token<'src> {
         // TODO: other number literals
+        let src = self.src;
+        let i = self.pos;
         let mut float = false;
-        let mut out = String::new();
         loop {
-            if self.chars_left() == 0 {
+            if self.at_eof() {
                 return if float {
-                    Token::FloatLit(out)
+                    Token::FloatLit(&src[i..self.pos])
                 } else {
-                    Token::IntLit(out)
+                    Token::IntLit(&src[i..self.pos])
                 };
             }
 
-            let c = self.peek_char();
+            let c = self.peek_char().expect("just checked not at eof");
             match c {
                 '0'..='9' | 'x' | 'b' => {
-                    out.push(self.take_char());
+                    self.take_char().expect("just peeked a char");
                 }
                 '.' => {
-                    out.push(self.take_char());
+                    self.take_char().expect("just peeked a char");
                     float = true;
                 }
                 _ => {
                     return if float {
-                        Token::FloatLit(out)
+                        Token::FloatLit(&src[i..self.pos])
                     } else {
-                        Token::IntLit(out)
+                        Token::IntLit(&src[i..self.pos])
                     };
                 }
             }
         }
     }
 
-    fn parse_ident(s: String) -> Token {
-        match s.as_str() {
+    fn parse_ident(s: &'src str) -> Token<'src> {
+        match s {
             "and" => Token::And,
             "as" => Token::As,
             "assert" => Token::Assert,
@@ -387,25 +811,76 @@ impl Lexer {
     }
 }
 
-impl Iterator for Lexer {
-    type Item = Token;
+impl<'src> Lexer<'src> {
+    /// Lex the next token, draining `self.pending` first if it already has
+    /// one buffered (from a prior peek, or from an f-string's tail).
+    fn lex(&mut self) -> Result<Option<(Token<'src>, Span)>, LexError> {
+        match self.pending.pop_front() {
+            Some(tok) => tok.map(Some),
+            None => self.lex_one(),
+        }
+    }
+
+    /// Ensure `self.pending` holds at least `n` tokens, lexing ahead as
+    /// needed. Stops early at the first `Ok(None)` (end of input) or
+    /// `Err`, in which case `self.pending` may still hold fewer than `n`.
+    fn fill_pending(&mut self, n: usize) {
+        while self.pending.len() < n {
+            let insert_at = self.pending.len();
+            match self.lex_one() {
+                Ok(Some(tok)) => self.pending.insert(insert_at, Ok(tok)),
+                Ok(None) => break,
+                Err(e) => {
+                    self.pending.insert(insert_at, Err(e));
+                    break;
+                }
+            }
+        }
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
+    /// Non-destructive lookahead at the next token.
+    pub fn peek_token(&mut self) -> Option<&Token<'src>> {
+        self.fill_pending(1);
+        self.pending.front()?.as_ref().ok().map(|(tok, _)| tok)
+    }
+
+    /// Non-destructive lookahead at the token after `peek_token`.
+    pub fn peek_second(&mut self) -> Option<&Token<'src>> {
+        self.fill_pending(2);
+        self.pending.get(1)?.as_ref().ok().map(|(tok, _)| tok)
+    }
+
+    /// Lex the next token straight from the source, or `Ok(None)` at end of
+    /// input. Ignores `self.pending`; callers that want to drain it first
+    /// should go through `lex`.
+    fn lex_one(&mut self) -> Result<Option<(Token<'src>, Span)>, LexError> {
+        let mut start;
+        let mut pos;
         let ret = loop {
-            if self.chars_left() == 0 {
-                return None;
+            if self.at_eof() {
+                return Ok(None);
             }
-            match self.take_char() {
+            start = self.pos;
+            pos = Position {
+                line: self.line,
+                col: self.col,
+            };
+            match self.take_char()? {
                 ' ' if self.start_of_line => {
                     let mut count = 1;
-                    while self.peek_char() == ' ' {
+                    while matches!(self.peek_char(), Ok(' ')) {
                         count += 1;
-                        self.take_char();
+                        self.take_char()?;
                     }
                     if count == 1 {
                         continue;
                     }
-                    assert_eq!(count % 4, 0);
+                    if count % 4 != 0 && self.brace_depth == 0 {
+                        return Err(LexError::BadIndent {
+                            count,
+                            span: self.here(),
+                        });
+                    }
                     break Token::Indent(count / 4);
                 }
                 '\n' => {
@@ -414,26 +889,31 @@ impl Iterator for Lexer {
                 c if c.is_ascii_whitespace() => {
                     continue;
                 }
-                // TODO: This needs to work for multiple tags: rf"hello"
-                'a'..='z' | 'A'..='Z' if matches!(self.peek_char(), '\'' | '"') => {
-                    self.pos -= 1;
-                    break self.take_str_lit();
+                'a'..='z' | 'A'..='Z' if self.peek_is_str_prefix_tail() => {
+                    self.untake_char();
+                    return Ok(Some(self.take_str_lit(start, pos)?));
                 }
                 'a'..='z' | 'A'..='Z' | '_' => {
-                    self.pos -= 1;
-                    break Self::parse_ident(String::from_iter(self.take_ident()));
+                    self.untake_char();
+                    break Self::parse_ident(self.take_ident());
                 }
 
                 '(' => break Token::LeftParen,
                 ')' => break Token::RightParen,
-                '{' => break Token::LeftCurly,
-                '}' => break Token::RightCurly,
+                '{' => {
+                    self.brace_depth += 1;
+                    break Token::LeftCurly;
+                }
+                '}' => {
+                    self.brace_depth = self.brace_depth.saturating_sub(1);
+                    break Token::RightCurly;
+                }
                 '[' => break Token::LeftSquare,
                 ']' => break Token::RightSquare,
 
                 ':' => {
-                    if self.peek_char() == '=' {
-                        self.take_char();
+                    if matches!(self.peek_char(), Ok('=')) {
+                        self.take_char()?;
                         break Token::ColonEquals;
                     }
                     break Token::Colon;
@@ -441,76 +921,78 @@ impl Iterator for Lexer {
                 ';' => break Token::SemiColon,
                 ',' => break Token::Comma,
                 '&' => match self.peek_char() {
-                    '=' => {
-                        self.take_char();
+                    Ok('=') => {
+                        self.take_char()?;
                         break Token::AmpersandEquals;
                     }
-                    '&' => {
-                        self.take_char();
+                    Ok('&') => {
+                        self.take_char()?;
                         break Token::DoubleAmpersand;
                     }
                     _ => break Token::Ampersand,
                 },
                 '|' => match self.peek_char() {
-                    '=' => {
-                        self.take_char();
+                    Ok('=') => {
+                        self.take_char()?;
                         break Token::PipeEquals;
                     }
-                    '|' => {
-                        self.take_char();
+                    Ok('|') => {
+                        self.take_char()?;
                         break Token::DoublePipe;
                     }
                     _ => break Token::Pipe,
                 },
                 '-' => match self.peek_char() {
-                    '=' => {
-                        self.take_char();
+                    Ok('=') => {
+                        self.take_char()?;
                         break Token::MinusEquals;
                     }
-                    '>' => {
-                        self.take_char();
+                    Ok('>') => {
+                        self.take_char()?;
                         break Token::ThinArrow;
                     }
                     _ => break Token::Minus,
                 },
                 '+' => {
-                    if self.peek_char() == '=' {
-                        self.take_char();
+                    if matches!(self.peek_char(), Ok('=')) {
+                        self.take_char()?;
                         break Token::PlusEquals;
                     }
                     break Token::Plus;
                 }
                 '%' => {
-                    if self.peek_char() == '=' {
-                        self.take_char();
+                    if matches!(self.peek_char(), Ok('=')) {
+                        self.take_char()?;
                         break Token::PercentEquals;
                     }
                     break Token::Percent;
                 }
 
                 '=' => {
-                    if self.peek_char() == '=' {
-                        self.take_char();
+                    if matches!(self.peek_char(), Ok('=')) {
+                        self.take_char()?;
                         break Token::DoubleEqual;
                     }
                     break Token::Equal;
                 }
-                '!' => {
-                    assert_eq!(
-                        self.peek_char(),
-                        '=',
-                        "expected '!=', got '!{}'",
-                        self.peek_char()
-                    );
-
-                    self.take_char();
-                    break Token::NotEqual;
-                }
+                '!' => match self.peek_char()? {
+                    '=' => {
+                        self.take_char()?;
+                        break Token::NotEqual;
+                    }
+                    actual => {
+                        return Err(LexError::ExpectedCharacter {
+                            expected: '=',
+                            actual,
+                            span: self.here(),
+                        });
+                    }
+                },
                 '/' => {
-                    if self.peek_char() == '/' {
-                        self.take_char();
-                        if self.peek_char() == '=' {
-                            self.take_char();
+                    if matches!(self.peek_char(), Ok('/')) {
+                        self.take_char()?;
+                        if matches!(self.peek_char(), Ok('=')) {
+                            self.take_char()?;
                             break Token::DoubleSlashEquals;
                         }
                         break Token::DoubleSlash;
@@ -518,30 +1000,30 @@ impl Iterator for Lexer {
                     break Token::Slash;
                 }
                 '*' => match self.peek_char() {
-                    '*' => {
-                        self.take_char();
-                        if self.peek_char() == '=' {
-                            self.take_char();
+                    Ok('*') => {
+                        self.take_char()?;
+                        if matches!(self.peek_char(), Ok('=')) {
+                            self.take_char()?;
                             break Token::DoubleAsteriskEquals;
                         }
                         break Token::DoubleAsterisk;
                     }
-                    '=' => {
-                        self.take_char();
+                    Ok('=') => {
+                        self.take_char()?;
                         break Token::AsteriskEquals;
                     }
                     _ => break Token::Asterisk,
                 },
                 '<' => {
-                    if self.peek_char() == '<' {
-                        self.take_char();
+                    if matches!(self.peek_char(), Ok('<')) {
+                        self.take_char()?;
                         break Token::LeftShift;
                     }
                     break Token::LeftAngle;
                 }
                 '>' => {
-                    if self.peek_char() == '>' {
-                        self.take_char();
+                    if matches!(self.peek_char(), Ok('>')) {
+                        self.take_char()?;
                         break Token::RightShift;
                     }
                     break Token::RightAngle;
@@ -550,31 +1032,44 @@ impl Iterator for Lexer {
                     break Token::Comment(self.take_comment());
                 }
                 '.' => {
-                    if self.peek_char().is_digit(10) {
-                        self.pos -= 1;
+                    if matches!(self.peek_char(), Ok(c) if c.is_ascii_digit()) {
+                        self.untake_char();
                         break self.take_number();
                     }
                     break Token::Dot;
                 }
                 '0'..='9' => {
-                    self.pos -= 1;
+                    self.untake_char();
                     break self.take_number();
                 }
                 '\'' | '"' => {
-                    self.pos -= 1;
-                    break self.take_str_lit();
-                }
-                c => unimplemented!(
-                    "'{}' nyi, context: \"{}\"",
-                    c,
-                    String::from_iter(
-                        &self.chars
-                            [(self.pos - 10).max(0)..(self.pos + 10).min(self.chars.len() - 1)]
-                    )
-                ),
+                    self.untake_char();
+                    return Ok(Some(self.take_str_lit(start, pos)?));
+                }
+                c => {
+                    return Err(LexError::UnexpectedCharacter {
+                        c,
+                        span: self.here(),
+                    });
+                }
             }
         };
         self.start_of_line = matches!(ret, Token::NewLine);
-        Some(ret)
+        Ok(Some((
+            ret,
+            Span {
+                start,
+                end: self.pos,
+                pos,
+            },
+        )))
+    }
+}
+
+impl<'src> Iterator for Lexer<'src> {
+    type Item = Result<(Token<'src>, Span), LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.lex().transpose()
     }
 }
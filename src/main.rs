@@ -1,11 +1,14 @@
 use std::{
     fs,
     io::{self, BufWriter},
+    process,
 };
 
 use lexer::Lexer;
+use transpiler::Transpiler;
 
 mod lexer;
+mod transpiler;
 
 fn main() -> io::Result<()> {
     let file = std::env::args().nth(1).expect("Usage: p3 <file> <out>");
@@ -13,8 +16,16 @@ fn main() -> io::Result<()> {
     let out = fs::File::create(out_path)?;
     let mut out = BufWriter::new(out);
     println!("reading");
-    for tok in Lexer::new(fs::read_to_string(file)?.chars().collect()) {
-        println!("{:?}", tok);
+    let contents = fs::read_to_string(file)?;
+    for tok in Transpiler::new(Lexer::new(&contents)) {
+        let (tok, span) = match tok {
+            Ok(tok) => tok,
+            Err(e) => {
+                eprintln!("{e}");
+                process::exit(1);
+            }
+        };
+        println!("{:?} {:?}", tok, span);
         tok.write_to(&mut out)?;
     }
     Ok(())
@@ -0,0 +1,162 @@
+use std::collections::VecDeque;
+
+use crate::lexer::{LexError, Lexer, Span, Token};
+
+/// Whether an open bracket is a transpiled block, or an ordinary
+/// paren/square-bracket/literal brace a block can be nested inside.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BracketKind {
+    Paren,
+    Square,
+    /// A `{` that is a dict/set literal, not a transpiled block.
+    LiteralCurly,
+    /// A `{` rewritten from a `Colon` that opens a block.
+    Block,
+    /// A `lambda` whose parameter list's closing `:` hasn't been seen yet;
+    /// that `:` separates params from body and is never a block opener.
+    Lambda,
+}
+
+/// Rewrites C-style `{ }` block braces into Python-style indentation,
+/// sitting between a [`Lexer`] and a consumer that expects indentation-only
+/// source (such as `Token::write_to`).
+///
+/// A `{` is only treated as opening a block when it immediately follows a
+/// `Colon` at statement position, i.e. not nested inside any `(`, `[`, or
+/// literal `{` a colon there could instead belong to (a slice or a dict
+/// entry), and not a `lambda`'s params/body separator; any other `{` (a
+/// dict or set literal) is passed through untouched, along with its
+/// matching `}`. Indentation inside an open block is generated purely from
+/// block depth, so the source's own leading whitespace there is dropped
+/// rather than stacked on top of it.
+pub struct Transpiler<'src> {
+    lexer: Lexer<'src>,
+    pending: VecDeque<Result<(Token<'src>, Span), LexError>>,
+    /// Number of block braces currently open.
+    depth: usize,
+    /// Every bracket currently open, in nesting order.
+    brackets: Vec<BracketKind>,
+}
+
+impl<'src> Transpiler<'src> {
+    pub fn new(lexer: Lexer<'src>) -> Self {
+        Self {
+            lexer,
+            pending: VecDeque::new(),
+            depth: 0,
+            brackets: Vec::new(),
+        }
+    }
+
+    /// Whether a `Colon` here could plausibly open a block: only true at
+    /// statement position, i.e. when every currently open bracket is
+    /// itself a block (not a paren/square/literal-curly, or a lambda
+    /// awaiting its params/body separator, a colon there could belong to
+    /// instead).
+    fn at_statement_position(&self) -> bool {
+        self.brackets.iter().all(|b| *b == BracketKind::Block)
+    }
+
+    /// Transpile the next token, or `Ok(None)` at end of input.
+    fn transpile(&mut self) -> Result<Option<(Token<'src>, Span)>, LexError> {
+        if let Some(tok) = self.pending.pop_front() {
+            return tok.map(Some);
+        }
+
+        loop {
+            let Some(next) = self.lexer.next() else {
+                return Ok(None);
+            };
+            let (tok, span) = next?;
+
+            match tok {
+                // Indentation inside a block comes entirely from `depth`;
+                // the source's own leading whitespace there would otherwise
+                // stack on top of it.
+                Token::Indent(_) if self.depth > 0 => continue,
+                Token::Lambda => {
+                    self.brackets.push(BracketKind::Lambda);
+                    return Ok(Some((Token::Lambda, span)));
+                }
+                // The `:` separating a lambda's params from its body is
+                // never a block opener, however it looks positionally.
+                Token::Colon if matches!(self.brackets.last(), Some(BracketKind::Lambda)) => {
+                    self.brackets.pop();
+                    return Ok(Some((Token::Colon, span)));
+                }
+                Token::Colon
+                    if self.at_statement_position()
+                        && matches!(self.lexer.peek_token(), Some(Token::LeftCurly)) =>
+                {
+                    // If the source already puts a newline right after the
+                    // `{` (the natural multi-line brace style), that real
+                    // `NewLine` will trigger its own indent below; adding a
+                    // synthetic one here too would double it up.
+                    let followed_by_newline =
+                        matches!(self.lexer.peek_second(), Some(Token::NewLine));
+                    self.lexer.next(); // consume the `{`
+                    self.brackets.push(BracketKind::Block);
+                    self.depth += 1;
+                    if !followed_by_newline {
+                        self.pending.push_back(Ok((Token::NewLine, span)));
+                        self.pending
+                            .push_back(Ok((Token::Indent(self.depth), span)));
+                    }
+                    return Ok(Some((Token::Colon, span)));
+                }
+                Token::LeftParen => {
+                    self.brackets.push(BracketKind::Paren);
+                    return Ok(Some((Token::LeftParen, span)));
+                }
+                Token::RightParen => {
+                    self.brackets.pop();
+                    return Ok(Some((Token::RightParen, span)));
+                }
+                Token::LeftSquare => {
+                    self.brackets.push(BracketKind::Square);
+                    return Ok(Some((Token::LeftSquare, span)));
+                }
+                Token::RightSquare => {
+                    self.brackets.pop();
+                    return Ok(Some((Token::RightSquare, span)));
+                }
+                Token::LeftCurly => {
+                    self.brackets.push(BracketKind::LiteralCurly);
+                    return Ok(Some((Token::LeftCurly, span)));
+                }
+                Token::RightCurly => {
+                    return match self.brackets.pop() {
+                        Some(BracketKind::Block) => {
+                            self.depth -= 1;
+                            if self.depth > 0 {
+                                self.pending
+                                    .push_back(Ok((Token::Indent(self.depth), span)));
+                            }
+                            Ok(Some((Token::NewLine, span)))
+                        }
+                        _ => Ok(Some((Token::RightCurly, span))),
+                    };
+                }
+                Token::SemiColon if self.depth > 0 => {
+                    self.pending
+                        .push_back(Ok((Token::Indent(self.depth), span)));
+                    return Ok(Some((Token::NewLine, span)));
+                }
+                Token::NewLine if self.depth > 0 => {
+                    self.pending
+                        .push_back(Ok((Token::Indent(self.depth), span)));
+                    return Ok(Some((Token::NewLine, span)));
+                }
+                tok => return Ok(Some((tok, span))),
+            }
+        }
+    }
+}
+
+impl<'src> Iterator for Transpiler<'src> {
+    type Item = Result<(Token<'src>, Span), LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.transpile().transpose()
+    }
+}